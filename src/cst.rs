@@ -0,0 +1,150 @@
+use std::ops::Range;
+use std::rc::Rc;
+
+use crate::error::Result as EzResult;
+use crate::lex::{self, FragmentKind};
+
+/// One lossless unit of a document: its kind, and the exact byte range (within the text it was
+/// parsed from) it spans. Immutable and `Rc`-shared, so `GreenTree::reparse` can hand back the
+/// very same node -- by pointer, not just by equal value -- for any node entirely before an edit.
+#[derive(Debug, PartialEq)]
+pub struct GreenNode {
+    pub kind: FragmentKind,
+    pub range: Range<usize>,
+}
+
+/// A lossless concrete syntax tree: a flat, ordered sequence of `GreenNode`s whose ranges, sliced
+/// out of the document they were built from and concatenated in order, reproduce it exactly
+/// byte-for-byte. Modeled on rowan's green tree, but flat rather than nested -- this grammar has
+/// no recursive structure (no fragment contains another), so one level already round-trips and
+/// supports incremental `reparse` without needing a tree of trees.
+///
+/// Unlike real rowan green nodes (which store only a length, so they're reusable verbatim after a
+/// shift elsewhere in the document), ours store an absolute byte range, so `reparse` has to
+/// rebuild (not reuse by identity) every node after an edit -- only nodes entirely *before* it are
+/// shared unchanged.
+#[derive(Debug, Clone)]
+pub struct GreenTree {
+    nodes: Vec<Rc<GreenNode>>,
+}
+
+impl GreenTree {
+    /// Parses the whole of `text` into a tree covering it exactly.
+    pub fn parse(text: &str) -> EzResult<GreenTree> {
+        let spans = lex::parse_document_spans(text)?;
+        Ok(GreenTree {
+            nodes: spans
+                .into_iter()
+                .map(|(kind, range)| Rc::new(GreenNode { kind, range }))
+                .collect(),
+        })
+    }
+
+    pub fn nodes(&self) -> &[Rc<GreenNode>] {
+        &self.nodes
+    }
+
+    /// Reconstructs the text this tree covers by slicing `source` with each node's range in
+    /// order. `tree.text(source) == source` for any `source` the tree was built (or reparsed)
+    /// from -- the defining property of a lossless tree.
+    pub fn text(&self, source: &str) -> String {
+        self.nodes.iter().map(|n| &source[n.range.clone()]).collect()
+    }
+
+    /// Re-lexes only the span covering `edit_range` and splices the result in, reusing every node
+    /// entirely before it (by `Rc` identity) and rebuilding -- with shifted ranges, same kind --
+    /// every node entirely after it. `old_text` is the text this tree currently covers; `edit`
+    /// replaces `edit_range` within it with `new_text`. Returns the new tree, plus the new full
+    /// text it covers.
+    pub fn reparse(
+        &self,
+        old_text: &str,
+        edit_range: Range<usize>,
+        new_text: &str,
+    ) -> EzResult<(GreenTree, String)> {
+        let delta = new_text.len() as isize - (edit_range.end as isize - edit_range.start as isize);
+
+        let before: Vec<_> = self
+            .nodes
+            .iter()
+            .take_while(|n| n.range.end <= edit_range.start)
+            .cloned()
+            .collect();
+        let after: Vec<_> = self
+            .nodes
+            .iter()
+            .skip(before.len())
+            .skip_while(|n| n.range.start < edit_range.end)
+            .collect();
+
+        let reparse_old_start = before.last().map_or(0, |n| n.range.end);
+        let reparse_old_end = after.first().map_or(old_text.len(), |n| n.range.start);
+
+        let mut full_text = String::with_capacity(
+            edit_range.start + new_text.len() + (old_text.len() - edit_range.end),
+        );
+        full_text.push_str(&old_text[..edit_range.start]);
+        full_text.push_str(new_text);
+        full_text.push_str(&old_text[edit_range.end..]);
+
+        let reparse_new_end = (reparse_old_end as isize + delta) as usize;
+        let reparsed = GreenTree::parse(&full_text[reparse_old_start..reparse_new_end])?;
+
+        let mut nodes = before;
+        nodes.extend(reparsed.nodes.into_iter().map(|n| {
+            Rc::new(GreenNode {
+                kind: n.kind,
+                range: (n.range.start + reparse_old_start)..(n.range.end + reparse_old_start),
+            })
+        }));
+        nodes.extend(after.into_iter().map(|n| {
+            Rc::new(GreenNode {
+                kind: n.kind,
+                range: shift(&n.range, delta),
+            })
+        }));
+
+        Ok((GreenTree { nodes }, full_text))
+    }
+}
+
+/// Shifts a byte range by `delta`, as when an edit earlier in the document changes its length.
+fn shift(range: &Range<usize>, delta: isize) -> Range<usize> {
+    ((range.start as isize + delta) as usize)..((range.end as isize + delta) as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn parse_round_trips() {
+        let text = "foo bar\n% documentation\nbaz qux% eol comment\n";
+        let tree = GreenTree::parse(text).unwrap();
+        assert_eq!(text, tree.text(text));
+    }
+
+    #[test]
+    fn reparse_round_trips_and_reuses_untouched_prefix() {
+        // A comment line between `foo bar` and `baz qux` gives the edit below a node boundary to
+        // land on -- `non_comment_chunk` otherwise spans a bare line break, merging `foo bar` and
+        // `baz qux` into a single `Source` node with no boundary between them to reuse.
+        let text = "foo bar\n% a comment\nbaz qux\n% documentation here\n";
+        let tree = GreenTree::parse(text).unwrap();
+
+        // Edit `baz qux` to `baz QUX!`, well after the first line and its comment.
+        let edit_start = text.find("baz qux").unwrap();
+        let edit_range = edit_start..(edit_start + "baz qux".len());
+        let (reparsed, new_text) = tree.reparse(text, edit_range, "baz QUX!").unwrap();
+
+        assert_eq!(
+            "foo bar\n% a comment\nbaz QUX!\n% documentation here\n",
+            new_text
+        );
+        assert_eq!(new_text, reparsed.text(&new_text));
+
+        // The first line's node is untouched, so it's the very same `Rc` allocation as before.
+        assert!(Rc::ptr_eq(&tree.nodes()[0], &reparsed.nodes()[0]));
+    }
+}
@@ -1,23 +1,28 @@
 use std::error;
 use std::fmt;
 use std::io;
+use std::path::PathBuf;
 
-use nom;
 use thiserror::{private::AsDynError, Error};
 use toml::de::Error as TomlError;
 
+use crate::lex::LexError;
+
 #[derive(Error, Debug, PartialEq)]
-pub enum Error<'a> {
+pub enum Error {
     // #[snafu(display("Unable to read configuration from {}: {}", path.display(), source))]
     // ReadConfiguration { source: io::Error, path: PathBuf },
 
     // #[snafu(display("Unable to write result to {}: {}", path.display(), source))]
     // WriteResult { source: io::Error, path: PathBuf },
-    #[error("Failed to parse document: {0:#?}")]
-    Lex(NomError<'a>),
+    #[error("Failed to parse document:\n{0}")]
+    Lex(LexError),
 
-    #[error("Failed to parse directives as TOML: {0:#?}")]
-    DirectivesParseToml(#[from] TomlError),
+    // `line` is the absolute line, in the original document, of the directive line the TOML
+    // error points at; `source`'s own line/col refer to the collapsed/unindented directive text,
+    // which isn't meaningful to show a user (see `parse::parse_directives_toml`).
+    #[error("Failed to parse directives as TOML (line {line}): {source}")]
+    DirectivesParseToml { line: u32, source: TomlError },
 
     #[error("Failed to open file: {0:#?}")]
     FileOpen(#[source] Box<IoError>),
@@ -28,23 +33,66 @@ pub enum Error<'a> {
     #[error("Failed to format: {0:#?}")]
     Format(#[from] fmt::Error),
 
-    #[error("No src_output or doc_output files provided")]
-    NoOutput,
+    #[error(
+        "The document declares named `targets`, but none of them matched the `--target` filter \
+         given on the command line"
+    )]
+    NoMatchingTarget,
+
+    #[error("Unterminated guard block: %<*{expr}> was never closed with a matching %</...>")]
+    UnterminatedGuard { expr: String },
+
+    #[error("Guard block closed with %</{closing}>, but the innermost open guard is %<*{opened}>")]
+    GuardMismatch { opened: String, closing: String },
+
+    #[error(
+        "Conflicting `{key}` directive: line {first_line} sets it to {first_value}, but line \
+         {second_line} sets it to {second_value}"
+    )]
+    ConflictingDirective {
+        key: &'static str,
+        first_line: u32,
+        first_value: String,
+        second_line: u32,
+        second_value: String,
+    },
+
+    #[error("Failed to read included file {path:?}: {source:#?}")]
+    IncludeRead {
+        path: PathBuf,
+        #[source]
+        source: Box<IoError>,
+    },
+
+    #[error(
+        "Cyclic include: {path:?} is already being processed, directly or indirectly, by the \
+         include that's trying to include it again"
+    )]
+    IncludeCycle { path: PathBuf },
 }
 
-impl<'a> Error<'a> {
-    pub fn file_open(err: io::Error) -> Error<'a> {
+impl Error {
+    pub fn file_open(err: io::Error) -> Error {
         Error::FileOpen(Box::new(IoError(err)))
     }
 
-    pub fn write(err: io::Error) -> Error<'a> {
+    pub fn write(err: io::Error) -> Error {
         Error::Write(Box::new(IoError(err)))
     }
-}
 
-pub type Result<'a, T, E = Error<'a>> = std::result::Result<T, E>;
+    pub fn directives_parse_toml(line: u32, source: TomlError) -> Error {
+        Error::DirectivesParseToml { line, source }
+    }
+
+    pub fn include_read(path: PathBuf, err: io::Error) -> Error {
+        Error::IncludeRead {
+            path,
+            source: Box::new(IoError(err)),
+        }
+    }
+}
 
-pub type NomError<'input> = nom::Err<(&'input str, nom::error::ErrorKind)>;
+pub type Result<T, E = Error> = std::result::Result<T, E>;
 
 #[derive(Debug)]
 pub struct IoError(io::Error);
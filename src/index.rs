@@ -0,0 +1,163 @@
+//! Associates documentation blocks with the command/environment definitions they precede, the
+//! way lix-doc walks a Nix file's definitions to pull each one's nearest preceding doc comment.
+//! `process::Process` uses `leading_definition` to build the mapping this module describes; the
+//! mapping itself is just `Entry` values collected in definition order.
+
+/// One documented command or environment: its name, a signature (name plus argument count, as
+/// written at its definition site) and the prose that preceded it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub name: String,
+    pub signature: String,
+    pub doc: String,
+}
+
+const COMMAND_DEFINERS: &[&str] = &["\\newcommand", "\\renewcommand", "\\DeclareRobustCommand"];
+
+/// If `src` begins (after leading whitespace) with a `\newcommand`, `\renewcommand`,
+/// `\DeclareRobustCommand`, `\def`, or `\newenvironment`, returns the name it defines and a
+/// signature describing it (name plus argument count) -- enough to label one `Entry`. Returns
+/// `None` for anything else, including a definition that isn't at the very start of `src`: only
+/// the chunk of source immediately after a documentation block counts as "following" it.
+pub fn leading_definition(src: &str) -> Option<(String, String)> {
+    let trimmed = src.trim_start();
+
+    if let Some(rest) = trimmed.strip_prefix("\\def") {
+        return def_definition(rest);
+    }
+    if let Some(rest) = trimmed.strip_prefix("\\newenvironment") {
+        return command_or_env_definition(rest, true);
+    }
+    for definer in COMMAND_DEFINERS {
+        if let Some(rest) = trimmed.strip_prefix(definer) {
+            return command_or_env_definition(rest, false);
+        }
+    }
+    None
+}
+
+/// Parses the name argument of a `\newcommand`-family definition: either braced (`{\foo}` for
+/// commands, `{foo}` for `\newenvironment`) or, for commands only, bare (`\foo`).
+fn take_name(rest: &str, is_env: bool) -> Option<(String, &str)> {
+    let rest = rest.trim_start();
+    if is_env {
+        let rest = rest.strip_prefix('{')?;
+        let end = rest.find('}')?;
+        Some((rest[..end].to_string(), &rest[end + 1..]))
+    } else if let Some(rest) = rest.strip_prefix('{') {
+        let rest = rest.strip_prefix('\\')?;
+        let end = rest.find(|c: char| !c.is_alphabetic()).unwrap_or(rest.len());
+        let (name, after) = rest.split_at(end);
+        let after = after.strip_prefix('}')?;
+        Some((format!("\\{}", name), after))
+    } else {
+        let rest = rest.strip_prefix('\\')?;
+        let end = rest.find(|c: char| !c.is_alphabetic()).unwrap_or(rest.len());
+        let (name, after) = rest.split_at(end);
+        Some((format!("\\{}", name), after))
+    }
+}
+
+/// Parses a `[n]` argument-count spec, if present immediately after the name.
+fn take_arg_count(rest: &str) -> Option<u32> {
+    let rest = rest.trim_start().strip_prefix('[')?;
+    let end = rest.find(']')?;
+    rest[..end].trim().parse().ok()
+}
+
+fn command_or_env_definition(rest: &str, is_env: bool) -> Option<(String, String)> {
+    let (name, after) = take_name(rest, is_env)?;
+    let signature = match take_arg_count(after) {
+        Some(n) => format!("{}[{}]", name, n),
+        None => name.clone(),
+    };
+    Some((name, signature))
+}
+
+/// Parses a `\def\name#1#2...{...}` definition: its parameter text (the `#1#2...` between the
+/// name and the replacement text's opening brace) stands in for `\newcommand`'s `[n]`.
+fn def_definition(rest: &str) -> Option<(String, String)> {
+    let rest = rest.trim_start().strip_prefix('\\')?;
+    let end = rest.find(|c: char| !c.is_alphabetic()).unwrap_or(rest.len());
+    let (name, after) = rest.split_at(end);
+    let name = format!("\\{}", name);
+
+    let params = &after[..after.find('{').unwrap_or(0)];
+    let signature = if params.trim().is_empty() {
+        name.clone()
+    } else {
+        format!("{}{}", name, params.trim())
+    };
+    Some((name, signature))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn newcommand_with_braced_name_and_arg_count() {
+        assert_eq!(
+            Some(("\\foo".to_string(), "\\foo[2]".to_string())),
+            leading_definition("\\newcommand{\\foo}[2]{#1 and #2}")
+        );
+    }
+
+    #[test]
+    fn newcommand_with_bare_name_and_no_args() {
+        assert_eq!(
+            Some(("\\foo".to_string(), "\\foo".to_string())),
+            leading_definition("\\newcommand\\foo{hello}")
+        );
+    }
+
+    #[test]
+    fn renewcommand_and_declare_robust_command_match_too() {
+        assert_eq!(
+            Some(("\\foo".to_string(), "\\foo".to_string())),
+            leading_definition("\\renewcommand{\\foo}{hello}")
+        );
+        assert_eq!(
+            Some(("\\foo".to_string(), "\\foo".to_string())),
+            leading_definition("\\DeclareRobustCommand{\\foo}{hello}")
+        );
+    }
+
+    #[test]
+    fn newenvironment_uses_unbackslashed_name() {
+        assert_eq!(
+            Some(("foo".to_string(), "foo[1]".to_string())),
+            leading_definition("\\newenvironment{foo}[1]{\\begin{center}}{\\end{center}}")
+        );
+    }
+
+    #[test]
+    fn def_records_parameter_text_as_the_signature() {
+        assert_eq!(
+            Some(("\\foo".to_string(), "\\foo#1#2".to_string())),
+            leading_definition("\\def\\foo#1#2{#1 and #2}")
+        );
+    }
+
+    #[test]
+    fn def_with_no_parameters_has_bare_signature() {
+        assert_eq!(
+            Some(("\\foo".to_string(), "\\foo".to_string())),
+            leading_definition("\\def\\foo{hello}")
+        );
+    }
+
+    #[test]
+    fn non_definitions_are_not_matched() {
+        assert_eq!(None, leading_definition("just some text\n"));
+    }
+
+    #[test]
+    fn leading_whitespace_before_the_definer_is_ignored() {
+        assert_eq!(
+            Some(("\\foo".to_string(), "\\foo".to_string())),
+            leading_definition("  \n\\newcommand\\foo{hello}")
+        );
+    }
+}
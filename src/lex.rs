@@ -7,27 +7,93 @@ use nom;
 use nom::IResult;
 use nom::{
     branch::alt,
-    bytes::complete::tag,
+    bytes::complete::{tag, take_until, take_while, take_while1},
     character::complete,
     character::complete::{anychar, line_ending, multispace0, none_of, not_line_ending},
     combinator::{complete, map, not, opt, recognize, value},
     multi::{fold_many0, many1, separated_nonempty_list},
-    sequence::{pair, preceded, terminated},
+    sequence::{delimited, pair, preceded, terminated},
 };
 
+use nom_locate::LocatedSpan;
+
 use unindent::unindent;
 
 use itertools::Itertools;
 
+use crate::error::{Error, Result as EzResult};
+
 const DIRECTIVE_TAG: &str = "%%%";
 const DOC_TAG: &str = "%%";
 const PRESERVED_COMMENT_TAG: &str = "%!";
+const TRAILING_DOC_TAG: &str = "%:";
 const EOL_COMMENT_TAG: char = '%';
+const GUARD_OPEN_TAG: &str = "%<";
+const GUARD_BLOCK_OPEN_TAG: &str = "%<*";
+const GUARD_BLOCK_CLOSE_TAG: &str = "%</";
+
+/// Lexer input: the original `&str` wrapped so every combinator carries a byte offset, line, and
+/// column alongside the text. Plain `&str` is recovered (via `fragment()`) at the point each
+/// `Chunk`/`Comment`/`GuardTag` is built, so nothing downstream of `lex` needs to know about
+/// spans -- only failed parses (see `LexError`) and directive line-mapping (see `parse.rs`) care
+/// about position.
+pub type Span<'a> = LocatedSpan<&'a str>;
+
+/// A line/column-positioned diagnostic for a failed `parse_document`, with a snippet of the
+/// offending line so the caller can render e.g. `line 12, col 4: unexpected input (Tag)`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LexError {
+    pub line: u32,
+    pub column: usize,
+    pub snippet: String,
+    pub kind: nom::error::ErrorKind,
+}
+
+impl Display for LexError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        writeln!(
+            f,
+            "line {}, col {}: unexpected input ({:?})",
+            self.line, self.column, self.kind
+        )?;
+        writeln!(f, "{}", self.snippet)?;
+        write!(f, "{}^", " ".repeat(self.column.saturating_sub(1)))
+    }
+}
+
+/// Builds a `LexError` from a failed top-level parse, looking up the offending line in the
+/// original (unwrapped) input by line number.
+fn lex_error(input: &str, err: nom::Err<(Span<'_>, nom::error::ErrorKind)>) -> LexError {
+    match err {
+        nom::Err::Error((span, kind)) | nom::Err::Failure((span, kind)) => {
+            let line = span.location_line();
+            let column = span.get_column();
+            let snippet = input
+                .lines()
+                .nth((line as usize).saturating_sub(1))
+                .unwrap_or("")
+                .to_string();
+            LexError {
+                line,
+                column,
+                snippet,
+                kind,
+            }
+        }
+        nom::Err::Incomplete(_) => LexError {
+            line: 0,
+            column: 0,
+            snippet: String::new(),
+            kind: nom::error::ErrorKind::Complete,
+        },
+    }
+}
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Chunk<'a> {
     Comment(Comment<String>),
     Source(&'a str),
+    Guard(GuardTag<'a>),
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -35,13 +101,73 @@ pub enum CommentKind {
     Directive,
     Documentation,
     Preserved,
+    /// A documentation comment trailing code on the same line (`foo{bar}%: what bar does`),
+    /// as opposed to a `Documentation` block, which stands on its own line(s). Routed to the
+    /// same documentation output, but -- since it's attached to a specific line of code rather
+    /// than standing alone -- written as-is rather than reflowed (see
+    /// `process::Process::process`).
+    TrailingDocumentation,
     Eol,
+    /// A docstrip-style guard tag (`%<expr>`, `%<*expr>`, or `%</expr>`). Unlike the other
+    /// kinds, a guard tag isn't a fixed string -- it carries a boolean `GuardExpr` -- so it's
+    /// only used to disambiguate tag-recognition (see `only_sol_comment_tag`); the actual guard
+    /// chunks are produced by `guard_block_start`, `guard_block_end`, and `guard_line` below.
+    Guard,
+}
+
+/// A boolean formula over option names, as written inside a guard tag (e.g. `plain|!draft`).
+#[derive(Clone, Debug, PartialEq)]
+pub enum GuardExpr {
+    Option(String),
+    Not(Box<GuardExpr>),
+    And(Box<GuardExpr>, Box<GuardExpr>),
+    Or(Box<GuardExpr>, Box<GuardExpr>),
+}
+
+impl GuardExpr {
+    /// Evaluates this formula against a set of currently-active option names.
+    pub fn eval(&self, active_options: &std::collections::HashSet<String>) -> bool {
+        match self {
+            GuardExpr::Option(name) => active_options.contains(name),
+            GuardExpr::Not(e) => !e.eval(active_options),
+            GuardExpr::And(a, b) => a.eval(active_options) && b.eval(active_options),
+            GuardExpr::Or(a, b) => a.eval(active_options) || b.eval(active_options),
+        }
+    }
+}
+
+impl Display for GuardExpr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            GuardExpr::Option(name) => write!(f, "{}", name),
+            GuardExpr::Not(e) => write!(f, "!{}", e),
+            GuardExpr::And(a, b) => write!(f, "({}&{})", a, b),
+            GuardExpr::Or(a, b) => write!(f, "({}|{})", a, b),
+        }
+    }
+}
+
+/// A single guard tag, as produced by the lexer. `expr` is the boolean formula over option
+/// names; `Process` evaluates it per-target to decide what gets emitted.
+#[derive(Clone, Debug, PartialEq)]
+pub enum GuardTag<'a> {
+    /// `%<expr> code`: `code` is only emitted into targets where `expr` evaluates true.
+    Line { expr: GuardExpr, code: &'a str },
+    /// `%<*expr>`: opens a block guard; everything until the matching `%</expr>` is covered by
+    /// `expr`.
+    BlockStart { expr: GuardExpr },
+    /// `%</expr>`: closes the block guard opened by the matching `%<*expr>`.
+    BlockEnd { expr: GuardExpr },
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Comment<T> {
     pub text: T,
     pub kind: CommentKind,
+    /// 1-indexed line, in the original document, where this comment (or, for a merged block,
+    /// its first line) started. Used to map errors in directive text -- which by the time it
+    /// reaches `toml`, has been unindented and stitched together -- back to real line numbers.
+    pub line: u32,
 }
 
 impl<T: Display> Display for Comment<T> {
@@ -57,6 +183,7 @@ impl Comment<&str> {
         Comment {
             text: String::from(self.text),
             kind: self.kind,
+            line: self.line,
         }
     }
 
@@ -69,6 +196,7 @@ impl Comment<&str> {
         Comment {
             text: text_new,
             kind: self.kind,
+            line: self.line,
         }
     }
 
@@ -76,6 +204,7 @@ impl Comment<&str> {
         Comment {
             text: self.text.trim_start().to_string(),
             kind: self.kind,
+            line: self.line,
         }
     }
 }
@@ -114,94 +243,237 @@ impl<'a> Iterator for ChunksIter<'a> {
 }
 
 /// Succeeds if the parser is at the end of input. Otherwise, returns an error.
-fn eof(input: &str) -> IResult<&str, ()> {
+fn eof(input: Span<'_>) -> IResult<Span<'_>, ()> {
     not(anychar)(input)
 }
 
-fn line_ending_or_eof(input: &str) -> IResult<&str, ()> {
+fn line_ending_or_eof(input: Span<'_>) -> IResult<Span<'_>, ()> {
     alt((value((), line_ending), eof))(input)
 }
 
 /// Recognizes an escaped character /\\./; this may be part of or an entire control sequence.
 /// (Note that /./ in the example regex does not include \n.)
-fn escaped(input: &str) -> IResult<&str, &str> {
+fn escaped(input: Span<'_>) -> IResult<Span<'_>, Span<'_>> {
     recognize(pair(complete::char('\\'), none_of("\r\n")))(input)
 }
 
-/// Recognizes as long a sequence of non-comment source code as possible (either a character
-/// /[^\\%\n]/, or an escape). Stops parsing when it finds a comment or a newline.
-fn non_comment(input: &str) -> IResult<&str, &str> {
+/// The inner grammar of `verb_inline`: `\verb` or `\verb*`, followed by any character as the
+/// delimiter, followed by everything up to (and including) the next occurrence of that same
+/// delimiter on the same line.
+fn verb_inline_body(input: Span<'_>) -> IResult<Span<'_>, char> {
+    let (rest, _) = alt((tag("\\verb*"), tag("\\verb")))(input)?;
+    let (rest, delim) = anychar(rest)?;
+    let (rest, _) = take_while(move |c: char| c != delim && c != '\n' && c != '\r')(rest)?;
+    complete::char(delim)(rest)
+}
+
+/// Recognizes a `\verb` or `\verb*` inline verbatim span (e.g. `\verb|50%|`) as a single literal
+/// unit, so that a `%` used as its contents -- rather than its delimiter -- isn't mistaken for the
+/// start of a comment.
+fn verb_inline(input: Span<'_>) -> IResult<Span<'_>, Span<'_>> {
+    recognize(verb_inline_body)(input)
+}
+
+/// Recognizes a `verbatim`/`lstlisting` environment (`\begin{verbatim}...\end{verbatim}`) as a
+/// single literal unit, including any line endings or `%` signs inside it.
+fn verbatim_env(input: Span<'_>) -> IResult<Span<'_>, Span<'_>> {
+    alt((
+        recognize(delimited(
+            tag("\\begin{verbatim}"),
+            take_until("\\end{verbatim}"),
+            tag("\\end{verbatim}"),
+        )),
+        recognize(delimited(
+            tag("\\begin{lstlisting}"),
+            take_until("\\end{lstlisting}"),
+            tag("\\end{lstlisting}"),
+        )),
+    ))(input)
+}
+
+/// Recognizes as long a sequence of non-comment source code as possible: a `verbatim`/
+/// `lstlisting` environment, a `\verb`/`\verb*` span, a character /[^\\%\n]/, or an escape. Stops
+/// parsing when it finds a comment or a newline not swallowed by one of the verbatim forms above.
+fn non_comment(input: Span<'_>) -> IResult<Span<'_>, Span<'_>> {
     recognize(many1(alt((
+        verbatim_env,
+        verb_inline,
         recognize(none_of("%\\\r\n")),
         recognize(escaped),
     ))))(input)
 }
 
 /// non_comment wrapped in a chunk.
-fn non_comment_chunk<'input>(input: &'input str) -> IResult<&'input str, Chunk<'input>> {
+fn non_comment_chunk<'input>(input: Span<'input>) -> IResult<Span<'input>, Chunk<'input>> {
     map(
         recognize(separated_nonempty_list(line_ending, non_comment)),
-        Chunk::Source,
+        |span: Span<'input>| Chunk::Source(span.fragment()),
     )(input)
 }
 
-fn directive_tag(input: &str) -> IResult<&str, CommentKind> {
+fn directive_tag(input: Span<'_>) -> IResult<Span<'_>, CommentKind> {
     value(CommentKind::Directive, tag(DIRECTIVE_TAG))(input)
 }
 
-fn documentation_tag(input: &str) -> IResult<&str, CommentKind> {
+fn documentation_tag(input: Span<'_>) -> IResult<Span<'_>, CommentKind> {
     value(CommentKind::Documentation, tag(DOC_TAG))(input)
 }
 
-fn preserved_tag(input: &str) -> IResult<&str, CommentKind> {
+fn preserved_tag(input: Span<'_>) -> IResult<Span<'_>, CommentKind> {
     value(CommentKind::Preserved, tag(PRESERVED_COMMENT_TAG))(input)
 }
 
-fn eol_tag(input: &str) -> IResult<&str, CommentKind> {
+fn trailing_doc_tag(input: Span<'_>) -> IResult<Span<'_>, CommentKind> {
+    value(CommentKind::TrailingDocumentation, tag(TRAILING_DOC_TAG))(input)
+}
+
+fn eol_tag(input: Span<'_>) -> IResult<Span<'_>, CommentKind> {
     value(CommentKind::Eol, complete::char(EOL_COMMENT_TAG))(input)
 }
 
-/// Parses a comment tag valid for an inline commennt; this includes preserved and eol tags.
-fn inline_comment_tag(input: &str) -> IResult<&str, CommentKind> {
-    alt((preserved_tag, eol_tag))(input)
+/// Recognizes (without consuming the guard expression) the start of any guard tag, so that
+/// `%<plain>...` isn't mistaken for an eol comment by `inline_comment`.
+fn guard_tag(input: Span<'_>) -> IResult<Span<'_>, CommentKind> {
+    value(CommentKind::Guard, tag(GUARD_OPEN_TAG))(input)
+}
+
+/// Parses a comment tag valid for an inline comment; this includes preserved, trailing-doc, and
+/// eol tags. `trailing_doc_tag` must be tried before `eol_tag`, since `eol_tag` only recognizes
+/// the bare `%` and would otherwise swallow the `:` of `%:` as the start of the comment's text.
+fn inline_comment_tag(input: Span<'_>) -> IResult<Span<'_>, CommentKind> {
+    alt((preserved_tag, trailing_doc_tag, eol_tag))(input)
 }
 
 /// Parses a comment tag valid *only* at the start of a line; doesn't include tags that are valid
 /// both for inline and start-of-line comments.
-fn only_sol_comment_tag(input: &str) -> IResult<&str, CommentKind> {
-    alt((directive_tag, documentation_tag))(input)
+fn only_sol_comment_tag(input: Span<'_>) -> IResult<Span<'_>, CommentKind> {
+    alt((directive_tag, documentation_tag, guard_tag))(input)
+}
+
+/// Recognizes an identifier naming a guard option (alphanumeric, `_`, or `-`).
+fn guard_ident(input: Span<'_>) -> IResult<Span<'_>, Span<'_>> {
+    take_while1(|c: char| c.is_alphanumeric() || c == '_' || c == '-')(input)
+}
+
+/// Atom of a guard formula: an option name, a parenthesized formula, or a negation.
+fn guard_atom(input: Span<'_>) -> IResult<Span<'_>, GuardExpr> {
+    alt((
+        map(preceded(complete::char('!'), guard_atom), |e| {
+            GuardExpr::Not(Box::new(e))
+        }),
+        delimited(complete::char('('), guard_expr, complete::char(')')),
+        map(guard_ident, |name| GuardExpr::Option(name.fragment().to_string())),
+    ))(input)
+}
+
+/// `&`-separated formula, binding tighter than `|`.
+fn guard_and(input: Span<'_>) -> IResult<Span<'_>, GuardExpr> {
+    map(
+        separated_nonempty_list(complete::char('&'), guard_atom),
+        |atoms| {
+            let mut atoms = atoms.into_iter();
+            let first = atoms.next().expect("separated_nonempty_list is nonempty");
+            atoms.fold(first, |acc, atom| GuardExpr::And(Box::new(acc), Box::new(atom)))
+        },
+    )(input)
+}
+
+/// A full guard formula: `|`-separated `guard_and` terms.
+fn guard_expr(input: Span<'_>) -> IResult<Span<'_>, GuardExpr> {
+    map(
+        separated_nonempty_list(complete::char('|'), guard_and),
+        |terms| {
+            let mut terms = terms.into_iter();
+            let first = terms.next().expect("separated_nonempty_list is nonempty");
+            terms.fold(first, |acc, term| GuardExpr::Or(Box::new(acc), Box::new(term)))
+        },
+    )(input)
+}
+
+/// `%<*expr>`: opens a block guard.
+fn guard_block_start(input: Span<'_>) -> IResult<Span<'_>, GuardTag<'_>> {
+    map(
+        delimited(tag(GUARD_BLOCK_OPEN_TAG), guard_expr, complete::char('>')),
+        |expr| GuardTag::BlockStart { expr },
+    )(input)
+}
+
+/// `%</expr>`: closes a block guard.
+fn guard_block_end(input: Span<'_>) -> IResult<Span<'_>, GuardTag<'_>> {
+    map(
+        delimited(tag(GUARD_BLOCK_CLOSE_TAG), guard_expr, complete::char('>')),
+        |expr| GuardTag::BlockEnd { expr },
+    )(input)
+}
+
+/// `%<expr> code`: a single-line guard.
+fn guard_line<'input>(input: Span<'input>) -> IResult<Span<'input>, GuardTag<'input>> {
+    map(
+        pair(
+            delimited(tag(GUARD_OPEN_TAG), guard_expr, complete::char('>')),
+            not_line_ending,
+        ),
+        |(expr, code): (GuardExpr, Span<'input>)| GuardTag::Line {
+            expr,
+            code: code.fragment(),
+        },
+    )(input)
+}
+
+/// Any guard tag, wrapped in a `Chunk`. Block tags are tried first since `guard_line` would
+/// otherwise happily parse the `*` of `%<*expr>` as... nothing, actually -- `*` isn't a valid
+/// `guard_ident` char, so ordering only matters for clarity here.
+fn guard_chunk<'input>(input: Span<'input>) -> IResult<Span<'input>, Chunk<'input>> {
+    map(
+        alt((guard_block_start, guard_block_end, guard_line)),
+        Chunk::Guard,
+    )(input)
 }
 
 /// Parses any comment tag.
-fn any_comment_tag(input: &str) -> IResult<&str, CommentKind> {
+fn any_comment_tag(input: Span<'_>) -> IResult<Span<'_>, CommentKind> {
     alt((only_sol_comment_tag, inline_comment_tag))(input)
 }
 
 /// An EOL-comment. Doesn't recognize special comments (e.g. directives or documentation), but will
 /// recognize preserved comments.
-fn inline_comment(input: &str) -> IResult<&str, Comment<&str>> {
+fn inline_comment<'input>(input: Span<'input>) -> IResult<Span<'input>, Comment<&'input str>> {
+    let line = input.location_line();
     preceded(
         not(only_sol_comment_tag),
-        map(pair(inline_comment_tag, not_line_ending), |(kind, text)| {
-            Comment { text, kind }
-        }),
+        map(
+            pair(inline_comment_tag, not_line_ending),
+            move |(kind, text): (CommentKind, Span<'input>)| Comment {
+                text: *text.fragment(),
+                kind,
+                line,
+            },
+        ),
     )(input)
 }
 
-fn inline_comment_chunk<'input>(input: &'input str) -> IResult<&'input str, Chunk<'input>> {
+fn inline_comment_chunk<'input>(input: Span<'input>) -> IResult<Span<'input>, Chunk<'input>> {
     map(inline_comment, |c| Chunk::Comment(c.trimmed()))(input)
 }
 
 /// Parses any comment.
-fn any_comment(input: &str) -> IResult<&str, Comment<&str>> {
-    map(pair(any_comment_tag, not_line_ending), |(kind, text)| {
-        Comment { kind, text }
-    })(input)
+fn any_comment<'input>(input: Span<'input>) -> IResult<Span<'input>, Comment<&'input str>> {
+    let line = input.location_line();
+    map(
+        // A guard tag is never a comment: it's left for `guard_chunk`, which also stops a comment
+        // block from swallowing a guard line that directly follows it.
+        pair(preceded(not(guard_tag), any_comment_tag), not_line_ending),
+        move |(kind, text): (CommentKind, Span<'input>)| Comment {
+            kind,
+            text: *text.fragment(),
+            line,
+        },
+    )(input)
 }
 
 /// A block of comments. Comment tags may be indented any amount, but non-comment source code is
 /// not allowed.
-fn any_comment_block(input: &str) -> IResult<&str, Vec<Comment<String>>> {
+fn any_comment_block(input: Span<'_>) -> IResult<Span<'_>, Vec<Comment<String>>> {
     map(
         separated_nonempty_list(pair(line_ending, multispace0), any_comment),
         collapse_comments,
@@ -209,14 +481,14 @@ fn any_comment_block(input: &str) -> IResult<&str, Vec<Comment<String>>> {
 }
 
 /// any_comment_block wrapped in `Chunk`s.
-fn any_comment_chunk<'input>(input: &'input str) -> IResult<&'input str, Vec<Chunk<'input>>> {
+fn any_comment_chunk<'input>(input: Span<'input>) -> IResult<Span<'input>, Vec<Chunk<'input>>> {
     map(any_comment_block, |comments| {
         comments.iter().cloned().map(Chunk::Comment).collect()
     })(input)
 }
 
 /// Collapses adjacent comments of the same `kind` into one comment with all the text concatenated
-/// and unindented.
+/// and unindented. The merged comment's `line` is that of its first constituent line.
 fn collapse_comments(comments: Vec<Comment<&str>>) -> Vec<Comment<String>> {
     match comments.len() {
         0 => Vec::with_capacity(0),
@@ -225,24 +497,33 @@ fn collapse_comments(comments: Vec<Comment<&str>>) -> Vec<Comment<String>> {
             .into_iter()
             .group_by(|c| c.kind)
             .into_iter()
-            .map(|(kind, mut group)| Comment {
-                kind,
-                text: unindent(&format!("\n{}", &group.join(""))),
+            .map(|(kind, group)| {
+                let group: Vec<_> = group.collect();
+                let line = group[0].line;
+                Comment {
+                    kind,
+                    text: unindent(&format!("\n{}", &group.into_iter().join(""))),
+                    line,
+                }
             })
             .collect(),
     }
 }
 
 fn parse_document_fragment<'input>(
-    input: &'input str,
-) -> IResult<&'input str, (Chunks<'input>, Option<Chunks<'input>>)> {
+    input: Span<'input>,
+) -> IResult<Span<'input>, (Chunks<'input>, Option<Chunks<'input>>)> {
     let non_comment = map(non_comment_chunk, Chunks::One);
     let inline_comment = map(inline_comment_chunk, Chunks::One);
     let any_comments = map(any_comment_chunk, Chunks::More);
+    let guard = map(guard_chunk, Chunks::One);
 
     alt((
         // Non-comment source followed by an optional inline comment and a line-end.
         terminated(pair(non_comment, opt(inline_comment)), line_ending_or_eof),
+        // A guard tag (line or block open/close); `any_comments` never matches one (see
+        // `any_comment`).
+        map(terminated(guard, line_ending_or_eof), |chunk| (chunk, None)),
         // A block of sol-comments; we map it to a tuple to satisfy the type-constraint from the
         // other branch.
         map(terminated(any_comments, line_ending_or_eof), |comments| {
@@ -251,7 +532,9 @@ fn parse_document_fragment<'input>(
     ))(input)
 }
 
-fn parse_document_greedy<'input>(input: &'input str) -> IResult<&'input str, Vec<Chunk<'input>>> {
+fn parse_document_greedy<'input>(
+    input: Span<'input>,
+) -> IResult<Span<'input>, Vec<Chunk<'input>>> {
     fold_many0(
         parse_document_fragment,
         Vec::<Chunk<'input>>::new(),
@@ -265,10 +548,102 @@ fn parse_document_greedy<'input>(input: &'input str) -> IResult<&'input str, Vec
     )(input)
 }
 
-pub fn parse_document<'input>(
-    input: &'input str,
-) -> Result<Vec<Chunk<'input>>, nom::Err<(&'input str, nom::error::ErrorKind)>> {
-    Ok(complete(parse_document_greedy)(input)?.1)
+pub fn parse_document<'input>(input: &'input str) -> EzResult<Vec<Chunk<'input>>> {
+    match complete(parse_document_greedy)(Span::new(input)) {
+        Ok((_, chunks)) => Ok(chunks),
+        Err(err) => Err(Error::Lex(lex_error(input, err))),
+    }
+}
+
+/// The coarse kind of one fragment, as produced by `parse_document_spans` -- enough for
+/// `cst::GreenTree` to label its nodes without needing the (lossy -- already unindented and
+/// merged across lines) `Chunk`/`Comment` values `parse_document` builds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FragmentKind {
+    Source,
+    Directive,
+    Documentation,
+    PreservedComment,
+    TrailingDocumentation,
+    Comment,
+    Guard,
+    /// The line ending (or, at end of file, nothing) terminating the fragment before it.
+    LineEnding,
+}
+
+impl From<CommentKind> for FragmentKind {
+    fn from(kind: CommentKind) -> Self {
+        match kind {
+            CommentKind::Directive => FragmentKind::Directive,
+            CommentKind::Documentation => FragmentKind::Documentation,
+            CommentKind::Preserved => FragmentKind::PreservedComment,
+            CommentKind::TrailingDocumentation => FragmentKind::TrailingDocumentation,
+            CommentKind::Eol => FragmentKind::Comment,
+            CommentKind::Guard => FragmentKind::Guard,
+        }
+    }
+}
+
+/// Parses `input` into `(FragmentKind, byte_range)` pairs that, read off `input` and concatenated
+/// in order, reproduce it exactly byte-for-byte -- unlike `parse_document`, which discards that
+/// information once comment text is trimmed, unindented, and merged across lines. This drives
+/// `parse_document_greedy`'s same grammar (`non_comment_chunk`, `inline_comment_chunk`,
+/// `guard_chunk`, `any_comment_chunk`), just recording where each fragment started and ended
+/// instead of building a `Chunk`. It's the raw material `cst::GreenTree` builds its lossless tree
+/// from.
+pub fn parse_document_spans(input: &str) -> EzResult<Vec<(FragmentKind, std::ops::Range<usize>)>> {
+    let mut span = Span::new(input);
+    let mut out = Vec::new();
+
+    while eof(span).is_err() {
+        let start = span.location_offset();
+
+        if let Ok((rest, _)) = non_comment_chunk(span) {
+            out.push((FragmentKind::Source, start..rest.location_offset()));
+            span = rest;
+            if let Ok((rest, comment)) = inline_comment_chunk(span) {
+                let kind = match comment {
+                    Chunk::Comment(c) => FragmentKind::from(c.kind),
+                    _ => unreachable!("inline_comment_chunk always produces Chunk::Comment"),
+                };
+                out.push((kind, span.location_offset()..rest.location_offset()));
+                span = rest;
+            }
+        } else if let Ok((rest, _)) = guard_chunk(span) {
+            out.push((FragmentKind::Guard, start..rest.location_offset()));
+            span = rest;
+        } else if let Ok((rest, comments)) = any_comment_chunk(span) {
+            let kind = comments
+                .first()
+                .map(|c| match c {
+                    Chunk::Comment(c) => FragmentKind::from(c.kind),
+                    _ => unreachable!("any_comment_chunk always produces Chunk::Comment"),
+                })
+                .unwrap_or(FragmentKind::Comment);
+            out.push((kind, start..rest.location_offset()));
+            span = rest;
+        } else {
+            return Err(Error::Lex(lex_error(
+                input,
+                nom::Err::Error((span, nom::error::ErrorKind::Alt)),
+            )));
+        }
+
+        let line_ending_result: IResult<Span<'_>, Span<'_>> = line_ending(span);
+        match line_ending_result {
+            Ok((rest, _)) => {
+                out.push((
+                    FragmentKind::LineEnding,
+                    span.location_offset()..rest.location_offset(),
+                ));
+                span = rest;
+            }
+            Err(_) if eof(span).is_ok() => break, // End of file without a trailing newline.
+            Err(err) => return Err(Error::Lex(lex_error(input, err))),
+        }
+    }
+
+    Ok(out)
 }
 
 #[cfg(test)]
@@ -287,6 +662,7 @@ mod tests {
         Chunk::Comment(Comment {
             text: unindent(text),
             kind,
+            line: 0,
         })
     }
 
@@ -310,52 +686,77 @@ mod tests {
         _comment(text, CommentKind::Eol)
     }
 
+    /// Utility function for creating a trailing documentation comment chunk.
+    fn trailing_doc(text: &str) -> Chunk<'_> {
+        _comment(text, CommentKind::TrailingDocumentation)
+    }
+
+    fn opt(name: &str) -> GuardExpr {
+        GuardExpr::Option(name.to_string())
+    }
+
+    /// Asserts two parsed documents are equal, ignoring the `line` field tracked on comments (the
+    /// fixtures above don't bother threading through the expected line numbers).
+    fn assert_parses_to(input: &str, expected: Vec<Chunk<'_>>) {
+        let mut actual = parse_document(input).expect("parse_document failed");
+        for chunk in &mut actual {
+            if let Chunk::Comment(c) = chunk {
+                c.line = 0;
+            }
+        }
+        assert_eq!(expected, actual);
+    }
+
     #[test]
     fn parse_empty() {
-        assert_eq!(Ok(vec![]), parse_document(""));
+        assert_parses_to("", vec![]);
     }
 
     #[test]
     fn parse_source_simple() {
-        assert_eq!(
-            Ok(vec![src(indoc!(
+        assert_parses_to(
+            indoc!(
                 "lorem ipsum dolor...
                  foo bar baz"
-            ))]),
-            parse_document(indoc!(
+            ),
+            vec![src(indoc!(
                 "lorem ipsum dolor...
                  foo bar baz"
-            ))
+            ))],
         );
 
-        assert_eq!(
-            Ok(vec![src("lorem ipsum dolor...")]),
-            parse_document("lorem ipsum dolor...")
+        assert_parses_to(
+            "lorem ipsum dolor...",
+            vec![src("lorem ipsum dolor...")],
         );
     }
 
     #[test]
     fn parse_eol_comment_simple() {
-        assert_eq!(
-            Ok(vec![
+        assert_parses_to(
+            "lorem ipsum dolor...% eol comment (thrown away)",
+            vec![
                 src("lorem ipsum dolor..."),
                 eol("eol comment (thrown away)"),
-            ]),
-            parse_document("lorem ipsum dolor...% eol comment (thrown away)")
+            ],
+        );
+    }
+
+    #[test]
+    fn parse_trailing_doc_comment() {
+        assert_parses_to(
+            "\\newcommand{\\foo}{bar}%: documents \\foo",
+            vec![
+                src("\\newcommand{\\foo}{bar}"),
+                trailing_doc("documents \\foo"),
+            ],
         );
     }
 
     #[test]
     fn parse_directives() {
-        assert_eq!(
-            Ok(vec![
-                dir("ezlatexdoc directives
-                    all come in blocks where each line starts with '%%%'
-                    whitespace before the markers is optional."),
-                eol("this plain comment will be thrown out..."),
-                dir("...but it breaks up the directive blocks into two."),
-            ]),
-            parse_document(indoc!(
+        assert_parses_to(
+            indoc!(
                 "
                 %%% ezlatexdoc directives
                 %%% all come in blocks where each line starts with '%%%'
@@ -363,14 +764,37 @@ mod tests {
                 % this plain comment will be thrown out...
                 %%% ...but it breaks up the directive blocks into two.
                 "
-            )),
+            ),
+            vec![
+                dir("ezlatexdoc directives
+                    all come in blocks where each line starts with '%%%'
+                    whitespace before the markers is optional."),
+                eol("this plain comment will be thrown out..."),
+                dir("...but it breaks up the directive blocks into two."),
+            ],
         );
     }
 
     #[test]
     fn parse_mixed() {
-        assert_eq!(
-            Ok(vec![
+        assert_parses_to(
+            indoc!(
+                "foo bar
+                foo bar baz
+                foo bar% eol
+                foo baz%! preserved
+                baz qux
+                %%% directives
+                %%% directives
+                %% documentation...
+                %% ...goes here, and doesn't even need to be in TeX
+                % impl note
+                more source...
+                foo bar baz
+                bux boz
+                "
+            ),
+            vec![
                 src(indoc!(
                     "foo bar
                      foo bar baz
@@ -389,23 +813,125 @@ mod tests {
                      foo bar baz
                      bux boz"
                 )),
-            ]),
-            parse_document(indoc!(
-                "foo bar
-                foo bar baz
-                foo bar% eol
-                foo baz%! preserved
-                baz qux
-                %%% directives
-                %%% directives
-                %% documentation...
-                %% ...goes here, and doesn't even need to be in TeX
-                % impl note
-                more source...
-                foo bar baz
-                bux boz
+            ],
+        );
+    }
+
+    #[test]
+    fn parse_guard_expr_atoms() {
+        assert_eq!(
+            Ok(opt("plain")),
+            guard_expr(Span::new("plain")).map(|(_, e)| e)
+        );
+        assert_eq!(
+            Ok(GuardExpr::Not(Box::new(opt("draft")))),
+            guard_expr(Span::new("!draft")).map(|(_, e)| e)
+        );
+    }
+
+    #[test]
+    fn parse_guard_expr_precedence() {
+        // `&` binds tighter than `|`: `plain|!draft&foo` == `plain|(!draft&foo)`.
+        assert_eq!(
+            Ok(GuardExpr::Or(
+                Box::new(opt("plain")),
+                Box::new(GuardExpr::And(
+                    Box::new(GuardExpr::Not(Box::new(opt("draft")))),
+                    Box::new(opt("foo")),
+                )),
+            )),
+            guard_expr(Span::new("plain|!draft&foo")).map(|(_, e)| e)
+        );
+        assert_eq!(
+            Ok(GuardExpr::Or(Box::new(opt("a")), Box::new(opt("b")))),
+            guard_expr(Span::new("(a|b)")).map(|(_, e)| e)
+        );
+    }
+
+    #[test]
+    fn parse_guard_line() {
+        assert_parses_to(
+            "%<plain>\\foo{bar}",
+            vec![Chunk::Guard(GuardTag::Line {
+                expr: opt("plain"),
+                code: "\\foo{bar}",
+            })],
+        );
+    }
+
+    #[test]
+    fn parse_guard_block() {
+        assert_parses_to(
+            indoc!(
+                "
+                %<*plain|!draft>
+                \\bar{baz}
+                %</plain|!draft>
                 "
-            ))
+            ),
+            vec![
+                Chunk::Guard(GuardTag::BlockStart {
+                    expr: GuardExpr::Or(
+                        Box::new(opt("plain")),
+                        Box::new(GuardExpr::Not(Box::new(opt("draft")))),
+                    ),
+                }),
+                src("\\bar{baz}"),
+                Chunk::Guard(GuardTag::BlockEnd {
+                    expr: GuardExpr::Or(
+                        Box::new(opt("plain")),
+                        Box::new(GuardExpr::Not(Box::new(opt("draft")))),
+                    ),
+                }),
+            ],
+        );
+    }
+
+    #[test]
+    fn parse_verb_inline_percent_is_not_a_comment() {
+        assert_parses_to(
+            "xyz \\verb|50%| of the total% real comment",
+            vec![
+                src("xyz \\verb|50%| of the total"),
+                eol("real comment"),
+            ],
+        );
+    }
+
+    #[test]
+    fn parse_verbatim_env_percent_is_not_a_comment() {
+        assert_parses_to(
+            indoc!(
+                "\\begin{verbatim}
+                 50% of the total
+                 \\end{verbatim}
+                 xyz% real comment"
+            ),
+            vec![
+                src(indoc!(
+                    "\\begin{verbatim}
+                     50% of the total
+                     \\end{verbatim}
+                     xyz"
+                )),
+                eol("real comment"),
+            ],
         );
     }
+
+    #[test]
+    fn lex_error_reports_line_column_and_snippet() {
+        let input = "line one\nline two, col four here\nline three";
+        // Pretend something failed 4 columns into "line two" (byte offset 12: 9 for "line one\n"
+        // plus 3 more into "line two").
+        let (remaining, _) =
+            nom::bytes::complete::take::<_, _, (Span<'_>, nom::error::ErrorKind)>(12usize)(
+                Span::new(input),
+            )
+            .unwrap();
+        let err = lex_error(input, nom::Err::Error((remaining, nom::error::ErrorKind::Tag)));
+        assert_eq!(2, err.line);
+        assert_eq!(4, err.column);
+        assert_eq!("line two, col four here", err.snippet);
+    }
 }
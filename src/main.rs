@@ -24,6 +24,17 @@ struct Opt {
     /// Input file
     #[structopt(parse(from_os_str))]
     input_files: Vec<PathBuf>,
+
+    /// Mark an option as active for guard evaluation (`%<expr>`/`%<*expr>...%</expr>`). May be
+    /// given multiple times. Only affects the document's default (unnamed) target; named
+    /// `targets` carry their own option sets.
+    #[structopt(long = "option", short = "o")]
+    active_options: Vec<String>,
+
+    /// Restrict processing to the named output target(s) declared by `targets` directives. If
+    /// unset, every declared target is processed.
+    #[structopt(long = "target", short = "t")]
+    targets: Vec<String>,
 }
 
 // quick_error! {
@@ -84,6 +95,7 @@ struct Opt {
 // }
 
 struct Run {
+    path: PathBuf,
     input: String,
 }
 
@@ -93,8 +105,9 @@ impl Run {
         P: AsRef<Path>,
     {
         Self {
+            path: path.as_ref().to_path_buf(),
             input: {
-                let mut reader = util::reader(path).unwrap();
+                let mut reader = util::reader(&path).unwrap();
                 let mut s = String::with_capacity(10_000);
                 reader.read_to_string(&mut s).unwrap();
                 s
@@ -102,17 +115,20 @@ impl Run {
         }
     }
 
-    pub fn process<'a>(&'a self) -> EzResult<'a, ()> {
-        let mut process = process::Process::default();
-        process.process_document(&self.input)?;
-        Ok(())
+    pub fn process(&self, active_options: Vec<String>, targets: Vec<String>) -> EzResult<()> {
+        let mut process = process::Process::default().with_active_options(active_options);
+        if !targets.is_empty() {
+            process = process.with_target_filter(targets);
+        }
+        process.process_document(&self.input, Some(&self.path))?;
+        process.finish()
     }
 }
 
 fn main() {
     let opt = Opt::from_args();
     for input_file in opt.input_files {
-        if let Err(e) = Run::new(input_file).process() {
+        if let Err(e) = Run::new(input_file).process(opt.active_options.clone(), opt.targets.clone()) {
             println!("Error: {}", e);
             exit(1);
         }
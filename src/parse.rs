@@ -1,22 +1,74 @@
+use std::collections::HashMap;
+
 use serde_derive::Deserialize;
 use toml;
 
 use crate::error::{Error, Result as EzResult};
-use crate::lex::{lex_document, Chunk, CommentKind};
+use crate::lex::{parse_document as lex_document, Chunk, CommentKind, GuardTag};
 
 #[derive(Debug, Clone)]
 pub enum Node<'a> {
     Source(&'a str),
-    Directives(Directives),
+    /// A directive block, and the 1-indexed line (in the original document) it started on --
+    /// kept around so conflicting redefinitions can be reported with both locations (see
+    /// `process::Process::record_directive`).
+    Directives(Directives, u32),
     Documentation(String),
     PreservedComment(String),
+    /// A documentation comment trailing code on the same line (see
+    /// `lex::CommentKind::TrailingDocumentation`), as opposed to a `Documentation` block
+    /// standing on its own.
+    TrailingDocumentation(String),
     Comment,
+    Guard(GuardTag<'a>),
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Debug, Clone, Default)]
 pub struct Directives {
     pub src_output: Option<String>,
     pub doc_output: Option<String>,
+    /// Where to write the generated command/environment index (see `index::Entry` and
+    /// `index::leading_definition`). Unset disables index generation entirely.
+    pub index_output: Option<String>,
+    /// Named output targets for docstrip-style conditional extraction: each target gets its own
+    /// `src_output` file and set of active options, against which guard expressions
+    /// (`%<expr>`/`%<*expr>...%</expr>`) are evaluated. When absent, `src_output` above is the
+    /// single default target, whose guards are evaluated against `Process`'s active options (see
+    /// `process::Process::with_active_options`).
+    pub targets: Option<HashMap<String, TargetDirectives>>,
+    /// Target line width for the documentation reflow pass (see `process::reflow_documentation`).
+    /// Defaults to 80 when unset.
+    pub doc_width: Option<usize>,
+    /// Other files to splice in at this point, as source and/or directives of their own. Paths
+    /// are resolved relative to `include_dir` if given, otherwise relative to the including
+    /// file's own directory.
+    pub include: Option<Vec<String>>,
+    /// Base directory `include` paths are resolved against, overriding the including file's own
+    /// directory.
+    pub include_dir: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct TargetDirectives {
+    pub src_output: String,
+    #[serde(default)]
+    pub options: Vec<String>,
+}
+
+/// Parses a directive block's (already unindented/concatenated) TOML text, mapping any error's
+/// position back onto the original document's line numbers.
+fn parse_directives_toml(comment: crate::lex::Comment<String>) -> EzResult<Directives> {
+    toml::from_str(&comment.text).map_err(|source| {
+        // `comment.text` is `unindent(format!("\n{}", ...))` (see `lex::collapse_comments`) for a
+        // multi-line block, or the one trimmed line otherwise. `unindent` drops that leading
+        // newline, so either way TOML's own (0-indexed) line 0 is the block's first line,
+        // `comment.line` in the original document.
+        let line = source
+            .line_col()
+            .map(|(line, _col)| comment.line + line as u32)
+            .unwrap_or(comment.line);
+        Error::directives_parse_toml(line, source)
+    })
 }
 
 pub fn parse_document<'input>(input: &'input str) -> EzResult<Vec<Node<'input>>> {
@@ -26,13 +78,17 @@ pub fn parse_document<'input>(input: &'input str) -> EzResult<Vec<Node<'input>>>
     for chunk in chunks {
         ret.push(match chunk {
             Chunk::Source(src) => Node::Source(src),
+            Chunk::Guard(guard) => Node::Guard(guard),
             Chunk::Comment(comment) => match comment.kind {
-                CommentKind::Directive => Node::Directives(
-                    toml::from_str(&comment.text).map_err(Error::DirectivesParseToml)?,
-                ),
+                CommentKind::Directive => {
+                    let line = comment.line;
+                    Node::Directives(parse_directives_toml(comment)?, line)
+                }
                 CommentKind::Documentation => Node::Documentation(comment.text),
                 CommentKind::Preserved => Node::PreservedComment(comment.text),
+                CommentKind::TrailingDocumentation => Node::TrailingDocumentation(comment.text),
                 CommentKind::Eol => Node::Comment,
+                CommentKind::Guard => unreachable!("guard tags are lexed as Chunk::Guard, not Chunk::Comment"),
             },
         });
     }
@@ -1,11 +1,17 @@
+use std::any::Any;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::fmt::Write;
-use std::fs::File;
 use std::io::Write as IoWrite;
+use std::path::{Path, PathBuf};
 
 use crate::error::{Error, Result as EzResult};
+use crate::index;
+use crate::lex::GuardTag;
 use crate::parse;
-use crate::parse::Node;
+use crate::parse::{Directives, Node};
 use crate::util;
+use crate::util::Writer;
 
 // /// The two output streams -- one stripped of documentation, one only for documentation -- of an
 // /// ezlatexdoc run.
@@ -18,77 +24,681 @@ use crate::util;
 // pub doc: D,
 // }
 
-const EXPECT_SRC_MSG: &'static str =
+const EXPECT_SRC_MSG: &str =
     "A src_output directive must be given before the first source text.";
-const EXPECT_DOC_MSG: &'static str =
+const EXPECT_DOC_MSG: &str =
     "A doc_output directive must be given before the first documentation text.";
+const DEFAULT_DOC_WIDTH: usize = 80;
+
+/// Renders the generated command/environment index as `doc_output`-style prose: each entry as
+/// its signature followed by its documentation, separated by blank lines.
+fn render_index(entries: &[index::Entry]) -> String {
+    let mut out = String::new();
+    for entry in entries {
+        let _ = writeln!(out, "{}", entry.signature);
+        let _ = writeln!(out, "{}", entry.doc.trim_end());
+        out.push('\n');
+    }
+    out
+}
+
+/// Drops leading/trailing blank lines from a documentation block, then strips the longest
+/// common leading-whitespace prefix shared by its remaining non-blank lines -- preserving each
+/// line's indentation *relative* to that shared prefix, and any single blank line left between
+/// paragraphs. Ported from rustc's `strip_doc_comment_decoration`, which does the same to turn a
+/// run of `///` line comments into clean Markdown; here it runs on a `Node::Documentation` block
+/// before `reflow_documentation` wraps it.
+pub fn strip_doc_decoration(text: &str) -> String {
+    let mut lines: Vec<&str> = text.lines().collect();
+    while lines.first().is_some_and(|line| line.trim().is_empty()) {
+        lines.remove(0);
+    }
+    while lines.last().is_some_and(|line| line.trim().is_empty()) {
+        lines.pop();
+    }
+
+    let prefix_len = lines
+        .iter()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.len() - line.trim_start().len())
+        .min()
+        .unwrap_or(0);
+
+    lines
+        .iter()
+        .map(|line| {
+            if line.trim().is_empty() {
+                ""
+            } else {
+                &line[prefix_len.min(line.len())..]
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Greedily word-wraps `text` to `width` columns, the way rustfmt's `rewrite_comment` wraps
+/// prose: paragraphs (runs of non-blank lines) are rewrapped independently, every blank line
+/// between them is kept (runs of blank lines are not collapsed), and any line that's already
+/// indented (nested lists, code samples written as `    foo`) is passed through untouched rather
+/// than folded into the surrounding prose.
+pub fn reflow_documentation(text: &str, width: usize) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut paragraph: Vec<&str> = Vec::new();
+
+    for line in text.lines() {
+        if line.trim().is_empty() {
+            flush_paragraph(&mut paragraph, width, &mut out);
+            out.push('\n');
+        } else if line.starts_with(' ') || line.starts_with('\t') {
+            flush_paragraph(&mut paragraph, width, &mut out);
+            out.push_str(line);
+            out.push('\n');
+        } else {
+            paragraph.push(line);
+        }
+    }
+    flush_paragraph(&mut paragraph, width, &mut out);
+
+    out
+}
+
+/// Greedily word-wraps one paragraph's worth of lines and appends the result to `out`, then
+/// clears `paragraph`. A no-op if `paragraph` is empty.
+fn flush_paragraph(paragraph: &mut Vec<&str>, width: usize, out: &mut String) {
+    if paragraph.is_empty() {
+        return;
+    }
+
+    let mut line_len = 0;
+    let mut at_line_start = true;
+    for word in paragraph.iter().flat_map(|line| line.split_whitespace()) {
+        if !at_line_start && line_len + 1 + word.len() > width {
+            out.push('\n');
+            line_len = 0;
+            at_line_start = true;
+        }
+        if !at_line_start {
+            out.push(' ');
+            line_len += 1;
+        }
+        out.push_str(word);
+        line_len += word.len();
+        at_line_start = false;
+    }
+    out.push('\n');
+    paragraph.clear();
+}
+
+/// A named output target declared via a `targets` directive: its own output stream, and the set
+/// of options active for it when evaluating guard expressions.
+struct Target {
+    output: Writer,
+    options: HashSet<String>,
+}
 
 pub struct Process {
-    src: String,
-    doc: String,
-    src_output: Option<File>,
-    doc_output: Option<File>,
+    src_output: Option<Writer>,
+    doc_output: Option<Writer>,
+    index_output: Option<Writer>,
+    /// Named targets declared via `targets` directives; empty unless the document uses them, in
+    /// which case `src_output` and `active_options` are ignored in favor of per-target routing,
+    /// with guards evaluated against each target's own options. May be
+    /// empty even though the document declared targets, if `target_filter` excluded all of them
+    /// -- `has_named_targets` is what distinguishes that from "document never used `targets`".
+    targets: HashMap<String, Target>,
+    /// Set once the document applies a `targets` directive, regardless of whether any of the
+    /// named targets survive `target_filter`. Lets `write_guarded` tell "no named targets were
+    /// ever declared" (fall back to the single default stream) apart from "they were all
+    /// filtered out" (an error, not a silent fallback to a stream the document never set up).
+    has_named_targets: bool,
+    /// Options considered active for the (single, unnamed) default target -- overridden from the
+    /// CLI with `--option`.
+    active_options: HashSet<String>,
+    /// If set, only these target names are opened/written to; others are skipped entirely.
+    target_filter: Option<HashSet<String>>,
+    /// Stack of guard expressions for block guards (`%<*expr>...%</expr>`) currently open,
+    /// innermost last.
+    guard_stack: Vec<crate::lex::GuardExpr>,
+    /// Target line width for the documentation reflow pass; overridden by a document's
+    /// `doc_width` directive.
+    doc_width: usize,
+    /// Each directive key's recorded value (type-erased, so directives of different types can
+    /// share one map -- see `record_directive`), its `Debug` text (for error messages only), and
+    /// the line it was first set on. Lets a directive be repeated -- by a later block in the same
+    /// document, or by a file that's `include`d more than once -- without either silently
+    /// shadowing the first value or re-running its side effects (e.g. reopening an output file).
+    applied_directives: HashMap<&'static str, (Box<dyn Any>, String, u32)>,
+    /// The file currently being processed, if any, used to resolve `include` paths relative to
+    /// it. Set for the duration of each `process_document` call, including nested ones.
+    current_path: Option<PathBuf>,
+    /// Canonicalized paths of files currently being included, directly or indirectly -- i.e. the
+    /// chain of `process_document` calls currently on the stack. Used to reject include cycles.
+    including: HashSet<PathBuf>,
+    /// How many `process_document` calls are currently on the stack: 1 while the top-level
+    /// document is processed, more while an `include` within it is.
+    document_depth: usize,
+    /// The most recently processed `Node::Documentation` block's text, if no other node has been
+    /// processed since -- i.e. if a `Node::Source` comes next, it's documented by this block (see
+    /// `index::leading_definition`). Cleared by any node other than `Documentation`.
+    pending_doc: Option<String>,
+    /// Commands/environments documented so far, in the order their definitions appeared, written
+    /// out by `finish` if `index_output` was set.
+    index: Vec<index::Entry>,
 }
 
 impl Default for Process {
     fn default() -> Self {
         Process {
-            // 10 kb
-            src: String::with_capacity(10_000),
-            doc: String::with_capacity(10_000),
             src_output: None,
             doc_output: None,
+            index_output: None,
+            targets: HashMap::new(),
+            has_named_targets: false,
+            active_options: HashSet::new(),
+            target_filter: None,
+            guard_stack: Vec::new(),
+            doc_width: DEFAULT_DOC_WIDTH,
+            applied_directives: HashMap::new(),
+            current_path: None,
+            including: HashSet::new(),
+            document_depth: 0,
+            pending_doc: None,
+            index: Vec::new(),
         }
     }
 }
 
 impl Process {
-    pub fn process_document<'input>(&mut self, input: &'input str) -> EzResult<'input, ()> {
-        for node in parse::parse_document(input)? {
+    /// Overrides which options are considered active for the default (unnamed) target, as with
+    /// `--option` on the CLI.
+    pub fn with_active_options(mut self, options: impl IntoIterator<Item = String>) -> Self {
+        self.active_options = options.into_iter().collect();
+        self
+    }
+
+    /// Restricts processing to only the named targets given, as with `--target` on the CLI. If
+    /// never called, all targets declared by the document are processed.
+    pub fn with_target_filter(mut self, targets: impl IntoIterator<Item = String>) -> Self {
+        self.target_filter = Some(targets.into_iter().collect());
+        self
+    }
+
+    /// Parses and processes `input`, writing guarded source/documentation to the outputs its
+    /// directives name. `path` is the file `input` was read from, if any -- used to resolve
+    /// `include` directives relative to it, and to reject an include cycle -- and is restored on
+    /// every return path, so a nested call (from an `include`) leaves the caller's own
+    /// `current_path` untouched.
+    ///
+    /// `path` is registered in `self.including` for the duration of this call (both for the
+    /// top-level document and for every nested `include`), so a file that includes itself is
+    /// rejected the moment the cycle closes, rather than after one full spurious reprocessing
+    /// pass of its own contents.
+    pub fn process_document(&mut self, input: &str, path: Option<&Path>) -> EzResult<()> {
+        let canonical = path
+            .map(|p| {
+                p.canonicalize()
+                    .map_err(|err| Error::include_read(p.to_path_buf(), err))
+            })
+            .transpose()?;
+        if let Some(canonical) = &canonical {
+            if !self.including.insert(canonical.clone()) {
+                return Err(Error::IncludeCycle {
+                    path: canonical.clone(),
+                });
+            }
+        }
+
+        let previous_path = self.current_path.take();
+        self.current_path = path.map(Path::to_path_buf);
+
+        self.document_depth += 1;
+        let result = self.process_document_inner(input);
+        self.document_depth -= 1;
+
+        self.current_path = previous_path;
+        if let Some(canonical) = canonical {
+            self.including.remove(&canonical);
+        }
+        result
+    }
+
+    fn process_document_inner(&mut self, input: &str) -> EzResult<()> {
+        let nodes = parse::parse_document(input)?;
+
+        for node in nodes {
             self.process(node)?;
         }
+
+        // Only check for an unterminated guard once we're back at the top of the include chain --
+        // a file in the middle of being `include`d might legitimately leave a guard open for its
+        // includer to close, or vice versa.
+        if self.document_depth == 1 {
+            if let Some(expr) = self.guard_stack.last() {
+                return Err(Error::UnterminatedGuard {
+                    expr: expr.to_string(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Records that directive `key` was set to `new_value` on `line`, returning `Ok(true)` if
+    /// this is the first time it's been set (so its side effect, e.g. opening a file, should run)
+    /// and `Ok(false)` if it repeats an already-recorded value (so the side effect should be
+    /// skipped). Errors if `new_value` conflicts with a previously recorded one, rather than
+    /// letting it silently shadow the earlier directive.
+    ///
+    /// Compares by real equality on the typed value (via `downcast_ref`), not by `Debug` text --
+    /// `HashMap`'s `Debug` impl doesn't print entries in a stable order, so two equal `targets`
+    /// directives could otherwise compare unequal and raise a spurious conflict.
+    fn record_directive<T: fmt::Debug + PartialEq + Clone + 'static>(
+        &mut self,
+        key: &'static str,
+        new_value: &T,
+        line: u32,
+    ) -> EzResult<bool> {
+        match self.applied_directives.get(key) {
+            Some((existing_value, _, _)) if existing_value.downcast_ref::<T>() == Some(new_value) => {
+                Ok(false)
+            }
+            Some((_, existing_debug, existing_line)) => {
+                Err(Error::ConflictingDirective {
+                    key,
+                    first_line: *existing_line,
+                    first_value: existing_debug.clone(),
+                    second_line: line,
+                    second_value: format!("{:?}", new_value),
+                })
+            }
+            None => {
+                self.applied_directives.insert(
+                    key,
+                    (Box::new(new_value.clone()), format!("{:?}", new_value), line),
+                );
+                Ok(true)
+            }
+        }
+    }
+
+    /// Returns true if every guard currently on the stack, plus `extra` (used for single-line
+    /// guards), is satisfied by `options`.
+    fn guards_satisfied(&self, options: &HashSet<String>, extra: Option<&crate::lex::GuardExpr>) -> bool {
+        self.guard_stack.iter().chain(extra).all(|expr| expr.eval(options))
+    }
+
+    /// Writes `text` into every target whose active guards are satisfied. Used for both
+    /// `Node::Source` (guarded only by the block stack) and `GuardTag::Line` (additionally
+    /// guarded by its own expression).
+    fn write_guarded(
+        &mut self,
+        text: &str,
+        extra: Option<&crate::lex::GuardExpr>,
+    ) -> EzResult<()> {
+        if self.targets.is_empty() {
+            if self.has_named_targets {
+                return Err(Error::NoMatchingTarget);
+            }
+            if self.guards_satisfied(&self.active_options, extra) {
+                write!(self.src_output.as_ref().expect(EXPECT_SRC_MSG), "{}", text)
+                    .map_err(Error::write)?;
+            }
+            return Ok(());
+        }
+        for target in self.targets.values() {
+            if self.guard_stack.iter().chain(extra).all(|expr| expr.eval(&target.options)) {
+                write!(&target.output, "{}", text).map_err(Error::write)?;
+            }
+        }
         Ok(())
     }
 
-    pub fn process<'input>(&mut self, node: Node<'input>) -> EzResult<'input, ()> {
+    pub fn process<'input>(&mut self, node: Node<'input>) -> EzResult<()> {
         match node {
-            Node::Source(src) => write!(self.src_output.as_ref().expect(EXPECT_SRC_MSG), "{}", src)
-                .map_err(Error::write),
+            Node::Source(src) => {
+                if let Some(doc) = self.pending_doc.take() {
+                    if let Some((name, signature)) = index::leading_definition(src) {
+                        self.index.push(index::Entry { name, signature, doc });
+                    }
+                }
+                self.write_guarded(src, None)
+            }
             Node::PreservedComment(c) => {
-                writeln!(self.src_output.as_ref().expect(EXPECT_SRC_MSG), "% {}", c)
-                    .map_err(Error::write)
+                self.pending_doc = None;
+                self.write_guarded(&format!("% {}\n", c), None)
             }
             Node::Comment => {
-                writeln!(self.src_output.as_ref().expect(EXPECT_SRC_MSG), "%").map_err(Error::write)
+                self.pending_doc = None;
+                self.write_guarded("%\n", None)
             }
             Node::Documentation(doc) => {
-                write!(self.doc_output.as_ref().expect(EXPECT_DOC_MSG), "{}", doc)
+                let stripped = strip_doc_decoration(&doc);
+                self.pending_doc = Some(stripped.clone());
+                let reflowed = reflow_documentation(&stripped, self.doc_width);
+                write!(
+                    self.doc_output.as_ref().expect(EXPECT_DOC_MSG),
+                    "{}",
+                    reflowed
+                )
+                .map_err(Error::write)
+            }
+            Node::TrailingDocumentation(doc) => {
+                // Unlike `Documentation`, this is attached to the one line of code it trailed,
+                // not to whatever comes after it -- it doesn't reflow (there's no paragraph to
+                // rewrap) and it doesn't set `pending_doc` (it isn't a preceding block for
+                // `index::leading_definition` to attach to a following definition).
+                self.pending_doc = None;
+                writeln!(self.doc_output.as_ref().expect(EXPECT_DOC_MSG), "{}", doc.trim())
                     .map_err(Error::write)
             }
-            Node::Directives(d) => {
-                if let Some(src_filename) = d.src_output {
-                    self.src_output = Some(util::open_new(src_filename).map_err(Error::file_open)?);
+            Node::Guard(GuardTag::Line { expr, code }) => {
+                self.pending_doc = None;
+                self.write_guarded(code, Some(&expr))
+            }
+            Node::Guard(GuardTag::BlockStart { expr }) => {
+                self.pending_doc = None;
+                self.guard_stack.push(expr);
+                Ok(())
+            }
+            Node::Guard(GuardTag::BlockEnd { expr }) => {
+                self.pending_doc = None;
+                match self.guard_stack.pop() {
+                    Some(opened) if opened == expr => Ok(()),
+                    Some(opened) => Err(Error::GuardMismatch {
+                        opened: opened.to_string(),
+                        closing: expr.to_string(),
+                    }),
+                    None => Err(Error::GuardMismatch {
+                        opened: String::new(),
+                        closing: expr.to_string(),
+                    }),
                 }
-                if let Some(doc_filename) = d.doc_output {
-                    self.doc_output = Some(util::open_new(doc_filename).map_err(Error::file_open)?);
+            }
+            Node::Directives(d, line) => {
+                self.pending_doc = None;
+                self.apply_directives(d, line)
+            }
+        }
+    }
+
+    /// Applies one directive block, encountered at `line`: opens the outputs it names, and
+    /// splices in any `include`d files at this point in the stream. A key that repeats an
+    /// already-applied value (e.g. from a directive block split by intervening comments, or
+    /// re-included by more than one file) is a no-op; a key that conflicts with one is an error
+    /// (see `record_directive`).
+    fn apply_directives(&mut self, d: Directives, line: u32) -> EzResult<()> {
+        if let Some(src_filename) = &d.src_output {
+            if self.record_directive("src_output", src_filename, line)? {
+                self.src_output =
+                    Some(util::open_new_or_stdout(src_filename).map_err(Error::file_open)?);
+            }
+        }
+        if let Some(doc_filename) = &d.doc_output {
+            if self.record_directive("doc_output", doc_filename, line)? {
+                self.doc_output =
+                    Some(util::open_new_or_stdout(doc_filename).map_err(Error::file_open)?);
+            }
+        }
+        if let Some(index_filename) = &d.index_output {
+            if self.record_directive("index_output", index_filename, line)? {
+                self.index_output =
+                    Some(util::open_new_or_stdout(index_filename).map_err(Error::file_open)?);
+            }
+        }
+        if let Some(doc_width) = d.doc_width {
+            if self.record_directive("doc_width", &doc_width, line)? {
+                self.doc_width = doc_width;
+            }
+        }
+        if let Some(targets) = &d.targets {
+            if self.record_directive("targets", targets, line)? {
+                self.has_named_targets = true;
+                for (name, target) in targets.clone() {
+                    if let Some(filter) = &self.target_filter {
+                        if !filter.contains(&name) {
+                            continue;
+                        }
+                    }
+                    let output =
+                        util::open_new_or_stdout(target.src_output).map_err(Error::file_open)?;
+                    self.targets.insert(
+                        name,
+                        Target {
+                            output,
+                            options: target.options.into_iter().collect(),
+                        },
+                    );
+                }
+            }
+        }
+        if let Some(includes) = &d.include {
+            if self.record_directive("include", includes, line)? {
+                for include in includes {
+                    self.include_file(include, d.include_dir.as_deref())?;
                 }
-                Ok(())
             }
         }
+        Ok(())
+    }
+
+    /// Resolves `include` (relative to `include_dir` if given, otherwise to the current file's
+    /// own directory) and recursively processes it in place, as if its contents appeared at this
+    /// point in the including document. Cycle detection happens in `process_document`, which this
+    /// delegates to.
+    fn include_file(&mut self, include: &str, include_dir: Option<&str>) -> EzResult<()> {
+        let base_dir = include_dir.map(PathBuf::from).unwrap_or_else(|| {
+            self.current_path
+                .as_ref()
+                .and_then(|path| path.parent())
+                .map(Path::to_path_buf)
+                .unwrap_or_default()
+        });
+        let resolved = base_dir.join(include);
+        let content = std::fs::read_to_string(&resolved)
+            .map_err(|err| Error::include_read(resolved.clone(), err))?;
+        self.process_document(&content, Some(&resolved))
+    }
+
+    /// Flushes the outputs `finish` alone is responsible for: the index manifest, if
+    /// `index_output` was set. `src_output`/`doc_output` are written incrementally as each node is
+    /// processed (see `write_guarded` and `process`), so they aren't touched here -- a document
+    /// that never uses documentation blocks is allowed to skip `doc_output` entirely, and
+    /// shouldn't be penalized for it just because `finish` ran.
+    pub fn finish(&self) -> EzResult<()> {
+        #[allow(clippy::unnecessary_unwrap)]
+        if self.index_output.is_some() {
+            write!(
+                self.index_output.as_ref().unwrap(),
+                "{}",
+                render_index(&self.index)
+            )
+            .map_err(Error::write)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn strip_doc_decoration_drops_leading_and_trailing_blank_lines() {
+        assert_eq!(
+            "foo\nbar",
+            strip_doc_decoration("\n\n  foo\n  bar\n\n")
+        );
+    }
+
+    #[test]
+    fn strip_doc_decoration_dedents_by_the_shortest_indented_line() {
+        assert_eq!(
+            "foo\n  bar\nbaz",
+            strip_doc_decoration("    foo\n      bar\n    baz")
+        );
+    }
+
+    #[test]
+    fn strip_doc_decoration_preserves_blank_lines_between_paragraphs() {
+        assert_eq!(
+            "foo\n\nbar",
+            strip_doc_decoration("  foo\n\n  bar")
+        );
+    }
+
+    #[test]
+    fn strip_doc_decoration_ignores_mixed_indentation_on_blank_lines() {
+        // A blank line with whitespace (but no other content) shouldn't count towards the shared
+        // indentation, or drag it below the other lines' two spaces.
+        assert_eq!(
+            "foo\n\nbar",
+            strip_doc_decoration("  foo\n\t\n  bar")
+        );
+    }
+
+    #[test]
+    fn reflow_documentation_wraps_a_paragraph_to_width() {
+        assert_eq!(
+            "one two\nthree\n",
+            reflow_documentation("one two three", 7)
+        );
+    }
+
+    #[test]
+    fn reflow_documentation_preserves_paragraph_breaks() {
+        assert_eq!(
+            "foo bar\n\nbaz qux\n",
+            reflow_documentation("foo bar\n\nbaz qux", 80)
+        );
+    }
+
+    #[test]
+    fn reflow_documentation_passes_through_indented_blocks_untouched() {
+        // An indented line (a code sample or nested list) is never folded into the surrounding
+        // prose, however long the resulting line, and doesn't start a new paragraph of its own.
+        assert_eq!(
+            "foo bar\n    code sample, untouched, no matter how long\nbaz qux\n",
+            reflow_documentation(
+                "foo bar\n    code sample, untouched, no matter how long\nbaz qux",
+                10
+            )
+        );
+    }
+
+    #[test]
+    fn reflow_documentation_rejoins_a_paragraph_split_across_lines() {
+        assert_eq!(
+            "foo bar baz qux\n",
+            reflow_documentation("foo bar\nbaz qux", 80)
+        );
+    }
+
+    #[test]
+    fn record_directive_first_write_succeeds() {
+        let mut process = Process::default();
+        assert_eq!(
+            Ok(true),
+            process.record_directive("src_output", &"out.tex".to_string(), 1)
+        );
+    }
+
+    #[test]
+    fn record_directive_repeat_of_the_same_value_is_a_no_op() {
+        let mut process = Process::default();
+        process
+            .record_directive("src_output", &"out.tex".to_string(), 1)
+            .unwrap();
+        assert_eq!(
+            Ok(false),
+            process.record_directive("src_output", &"out.tex".to_string(), 2)
+        );
+    }
+
+    #[test]
+    fn record_directive_conflicting_value_is_an_error() {
+        let mut process = Process::default();
+        process
+            .record_directive("src_output", &"out.tex".to_string(), 1)
+            .unwrap();
+        assert_eq!(
+            Err(Error::ConflictingDirective {
+                key: "src_output",
+                first_line: 1,
+                first_value: "\"out.tex\"".to_string(),
+                second_line: 2,
+                second_value: "\"other.tex\"".to_string(),
+            }),
+            process.record_directive("src_output", &"other.tex".to_string(), 2)
+        );
+    }
+
+    #[test]
+    fn record_directive_compares_hash_maps_by_value_not_debug_text() {
+        // `HashMap`'s `Debug` impl doesn't print entries in a stable order, so two maps built in
+        // a different insertion order -- but otherwise equal -- must still compare equal here,
+        // rather than spuriously conflicting (see `record_directive`'s doc comment).
+        let mut first = HashMap::new();
+        first.insert("a".to_string(), 1);
+        first.insert("b".to_string(), 2);
+        let mut second = HashMap::new();
+        second.insert("b".to_string(), 2);
+        second.insert("a".to_string(), 1);
+
+        let mut process = Process::default();
+        process.record_directive("targets", &first, 1).unwrap();
+        assert_eq!(Ok(false), process.record_directive("targets", &second, 2));
+    }
+
+    /// A temporary file path that deletes itself on drop, so a test can assert on a `Writer`'s
+    /// real file contents without leaking the file if the assertion panics.
+    struct TempPath(PathBuf);
+
+    impl TempPath {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("{}_{}.tex", name, std::process::id()));
+            let _ = std::fs::remove_file(&path);
+            TempPath(path)
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn targets_only_document_does_not_panic_on_a_plain_comment() {
+        // Regression test: a document that only ever declares named `targets` (no default
+        // `src_output`) used to panic on the very first `Node::PreservedComment`/`Node::Comment`,
+        // because those two arms wrote directly to `self.src_output` instead of going through
+        // `write_guarded` like `Node::Source` already did.
+        let out = TempPath::new("ezlatexdoc_targets_only_test");
+        let input = format!(
+            "%%% targets.a.src_output = {:?}\nfoo bar\n% just a comment\n",
+            out.0.to_str().unwrap()
+        );
+
+        let mut process = Process::default();
+        process.process_document(&input, None).unwrap();
+
+        let written = std::fs::read_to_string(&out.0).unwrap();
+        assert_eq!("foo bar%\n", written);
     }
 
-    pub fn finish<'s, 'a>(&'s self) -> EzResult<'a, ()> {
-        write!(
-            self.src_output.as_ref().ok_or(Error::NoOutput)?,
-            "{}",
-            self.src
-        )
-        .map_err(Error::write)?;
-        write!(
-            self.doc_output.as_ref().ok_or(Error::NoOutput)?,
-            "{}",
-            self.doc
-        )
-        .map_err(Error::write)
+    #[test]
+    fn targets_only_document_does_not_panic_on_a_preserved_comment() {
+        let out = TempPath::new("ezlatexdoc_targets_only_preserved_test");
+        let input = format!(
+            "%%% targets.a.src_output = {:?}\nfoo bar\n%! keep me\n",
+            out.0.to_str().unwrap()
+        );
+
+        let mut process = Process::default();
+        process.process_document(&input, None).unwrap();
+
+        let written = std::fs::read_to_string(&out.0).unwrap();
+        assert_eq!("foo bar% keep me\n", written);
     }
 }
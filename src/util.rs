@@ -33,9 +33,42 @@ impl Write for Writer {
     }
 }
 
+/// Also needed so `write!`/`writeln!` can target a `&Process`-held `Writer` without requiring
+/// `&mut` access to it (the same trick `std` plays for `&File` and `&Stdout`, both of which are
+/// just handles onto OS-managed, independently-writable state).
+impl Write for &Writer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Writer::File(f) => (&*f).write(buf),
+            Writer::Stdout(s) => (&*s).write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Writer::File(f) => (&*f).flush(),
+            Writer::Stdout(s) => (&*s).flush(),
+        }
+    }
+}
+
 pub fn open_new<P>(path: P) -> io::Result<File>
 where
     P: AsRef<Path>,
 {
     OpenOptions::new().write(true).create_new(true).open(path)
 }
+
+/// Opens `path` for writing the way a `src_output`/`doc_output`/`index_output`/target directive
+/// value does: `-` selects stdout, so a declared stream can be piped instead of written to a
+/// file; anything else is passed to `open_new`.
+pub fn open_new_or_stdout<P>(path: P) -> io::Result<Writer>
+where
+    P: AsRef<Path>,
+{
+    if path.as_ref() == Path::new("-") {
+        Ok(Writer::Stdout(io::stdout()))
+    } else {
+        open_new(path).map(Writer::File)
+    }
+}